@@ -0,0 +1,564 @@
+use anyhow::{anyhow, Error, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+/// Separates the comment content from the trailer block
+const TRAILER_SEPARATOR: &str = "\n\n---\n";
+
+/// Github rejects comments longer than this many characters
+pub const MAX_COMMENT_LENGTH: usize = 65536;
+
+/// Marks the end of a truncated comment
+const TRUNCATION_NOTICE: &str = "\n\n... [truncated]";
+
+lazy_static! {
+    /// A `${VAR}` reference to an environment variable, or the `$$` escape of a literal `$`
+    static ref ENV_VAR_PATTERN: Regex = Regex::new(r"\$\$|\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    /// A `{{name}}` placeholder of a comment template
+    static ref PLACEHOLDER_PATTERN: Regex = Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap();
+}
+
+/// A `Key: value` line appended at the end of the comment, used to attribute
+/// the comment to a run or user (e.g. `Generated-by: tool @ run 123`)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Trailer {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for Trailer {
+    type Err = Error;
+
+    /// Parse a trailer from its `key=value` command line representation
+    fn from_str(s: &str) -> Result<Trailer> {
+        let mut parts = s.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.trim().is_empty() => Ok(Trailer {
+                key: key.trim().to_owned(),
+                value: value.trim().to_owned(),
+            }),
+            _ => Err(anyhow!("Trailer `{}` is not of the form key=value", s)),
+        }
+    }
+}
+
+impl fmt::Display for Trailer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.value)
+    }
+}
+
+/// Append the trailer block at the end of the comment, leaving it untouched if
+/// there is no trailer
+pub fn append_trailers(comment: &str, trailers: &[Trailer]) -> String {
+    if trailers.is_empty() {
+        return comment.to_owned();
+    }
+    let block: Vec<String> = trailers.iter().map(ToString::to_string).collect();
+    format!("{}{}{}", comment, TRAILER_SEPARATOR, block.join("\n"))
+}
+
+/// The comment without its trailer block, recognized by the keys of the given trailers, e.g. to
+/// add content to a previous comment without repeating its trailers
+pub fn remove_trailers<'a>(comment: &'a str, trailers: &[Trailer]) -> &'a str {
+    let start = match comment.rfind(TRAILER_SEPARATOR) {
+        Some(start) if !trailers.is_empty() => start,
+        _ => return comment,
+    };
+    let is_trailer = |line: &str| {
+        trailers
+            .iter()
+            .any(|trailer| line.starts_with(&format!("{}: ", trailer.key)))
+    };
+    if comment[start + TRAILER_SEPARATOR.len()..]
+        .lines()
+        .all(is_trailer)
+    {
+        &comment[..start]
+    } else {
+        comment
+    }
+}
+
+/// Wrap the comment between the header and the footer, separated from it by an empty line
+pub fn frame_comment(comment: &str, header: Option<&str>, footer: Option<&str>) -> String {
+    let mut framed = String::new();
+    if let Some(header) = header {
+        framed.push_str(header);
+        framed.push_str("\n\n");
+    }
+    framed.push_str(comment);
+    if let Some(footer) = footer {
+        framed.push_str("\n\n");
+        framed.push_str(footer);
+    }
+    framed
+}
+
+/// Truncate the comment so that it is at most `max_length` characters long, truncation notice
+/// included
+pub fn truncate_comment(comment: &str, max_length: usize) -> String {
+    if comment.chars().count() <= max_length {
+        return comment.to_owned();
+    }
+    let kept = max_length.saturating_sub(TRUNCATION_NOTICE.chars().count());
+    let truncated: String = comment.chars().take(kept).collect();
+    format!("{}{}", truncated, TRUNCATION_NOTICE)
+}
+
+/// Substitute the `${VAR}` references with the value given by `lookup`, `$$` standing for a literal
+/// `$`. Unknown variables are left as is, unless `strict` where they are an error.
+pub fn expand_env<F: Fn(&str) -> Option<String>>(
+    comment: &str,
+    lookup: F,
+    strict: bool,
+) -> Result<String> {
+    let mut expanded = String::with_capacity(comment.len());
+    let mut last = 0;
+    for captures in ENV_VAR_PATTERN.captures_iter(comment) {
+        let reference = captures.get(0).unwrap();
+        expanded.push_str(&comment[last..reference.start()]);
+        last = reference.end();
+        let name = match captures.get(1) {
+            Some(name) => name.as_str(),
+            None => {
+                expanded.push('$');
+                continue;
+            }
+        };
+        match lookup(name) {
+            Some(value) => expanded.push_str(&value),
+            None if strict => return Err(anyhow!("Environment variable `{}` is not set", name)),
+            None => expanded.push_str(reference.as_str()),
+        }
+    }
+    expanded.push_str(&comment[last..]);
+    Ok(expanded)
+}
+
+/// The names of the `{{name}}` placeholders of the comment
+pub fn template_placeholders(comment: &str) -> Vec<&str> {
+    PLACEHOLDER_PATTERN
+        .captures_iter(comment)
+        .map(|captures| captures.get(1).unwrap().as_str())
+        .collect()
+}
+
+/// Substitute the `{{name}}` placeholders with the value given by `lookup`, the unknown ones being
+/// left as is
+pub fn fill_template<F: Fn(&str) -> Option<String>>(comment: &str, lookup: F) -> String {
+    PLACEHOLDER_PATTERN
+        .replace_all(comment, |captures: &regex::Captures| {
+            lookup(&captures[1]).unwrap_or_else(|| captures[0].to_owned())
+        })
+        .into_owned()
+}
+
+/// Convert the CRLF line endings, e.g. of a file written on Windows, to LF
+pub fn normalize_eol(comment: &str) -> String {
+    comment.replace("\r\n", "\n")
+}
+
+/// Fingerprint of the comment content, recorded in the metadata to detect unchanged content
+pub fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// The code fence opening a code block, e.g. "```rust"
+struct CodeFence {
+    line: usize,
+    fence_char: char,
+    length: usize,
+}
+
+/// The code fence of the line, three backticks or tildes or more indented by at most 3 spaces
+fn code_fence(line: usize, text: &str) -> Option<CodeFence> {
+    let unindented = text.trim_start_matches(' ');
+    if text.len() - unindented.len() > 3 {
+        return None;
+    }
+    let fence_char = unindented
+        .chars()
+        .next()
+        .filter(|c| *c == '`' || *c == '~')?;
+    let length = unindented.chars().take_while(|c| *c == fence_char).count();
+    if length >= 3 {
+        Some(CodeFence {
+            line,
+            fence_char,
+            length,
+        })
+    } else {
+        None
+    }
+}
+
+/// The markdown mistakes breaking the rendering of the comment: the code blocks and HTML comments
+/// never closed, which swallow the rest of the comment. The comment is expected without its
+/// metadata, whose HTML comment is well formed
+pub fn lint_markdown(comment: &str) -> Vec<String> {
+    let mut open_fence: Option<CodeFence> = None;
+    let mut open_html_comment: Option<usize> = None;
+    for (index, text) in comment.lines().enumerate() {
+        let line = index + 1;
+        if open_html_comment.is_none() {
+            match (&open_fence, code_fence(line, text)) {
+                (Some(open), Some(fence))
+                    if fence.fence_char == open.fence_char
+                        && fence.length >= open.length
+                        && text.trim().chars().all(|c| c == fence.fence_char) =>
+                {
+                    open_fence = None;
+                    continue;
+                }
+                (Some(_), _) => continue,
+                (None, Some(fence)) => {
+                    open_fence = Some(fence);
+                    continue;
+                }
+                (None, None) => {}
+            }
+        }
+        let mut rest = text;
+        loop {
+            match open_html_comment {
+                Some(_) => match rest.find("-->") {
+                    Some(end) => {
+                        open_html_comment = None;
+                        rest = &rest[end + 3..];
+                    }
+                    None => break,
+                },
+                None => match rest.find("<!--") {
+                    Some(start) => {
+                        open_html_comment = Some(line);
+                        rest = &rest[start + 4..];
+                    }
+                    None => break,
+                },
+            }
+        }
+    }
+
+    let mut warnings = vec![];
+    if let Some(fence) = open_fence {
+        warnings.push(format!(
+            "The code block opened on line {} is never closed, the rest of the comment being \
+             rendered as code",
+            fence.line
+        ));
+    }
+    if let Some(line) = open_html_comment {
+        warnings.push(format!(
+            "The HTML comment opened on line {} is never closed, hiding the rest of the comment",
+            line
+        ));
+    }
+    warnings
+}
+
+/// Header prepended to every part of a split comment
+fn part_header(part: usize, parts: usize) -> String {
+    format!("(part {} of {})\n\n", part, parts)
+}
+
+/// Split the comment on line boundaries in parts of at most `max_length` characters, part header
+/// included. Lines too long to fit in a single part are split anywhere.
+pub fn split_comment(comment: &str, max_length: usize) -> Vec<String> {
+    if comment.chars().count() <= max_length {
+        return vec![comment.to_owned()];
+    }
+    // Leave room for the header, assuming there won't be more than 999 parts
+    let chunk_length = max_length
+        .saturating_sub(part_header(999, 999).chars().count())
+        .max(1);
+
+    let mut chunks: Vec<String> = vec![];
+    let mut current = String::new();
+    let mut current_length = 0;
+    for line in comment.split_inclusive('\n') {
+        let line_length = line.chars().count();
+        if current_length + line_length > chunk_length && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+            current_length = 0;
+        }
+        if line_length > chunk_length {
+            let chars: Vec<char> = line.chars().collect();
+            let mut pieces: Vec<String> = chars
+                .chunks(chunk_length)
+                .map(|piece| piece.iter().collect())
+                .collect();
+            current = pieces.pop().unwrap_or_default();
+            current_length = current.chars().count();
+            chunks.extend(pieces);
+        } else {
+            current.push_str(line);
+            current_length += line_length;
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    let parts = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| format!("{}{}", part_header(i + 1, parts), chunk))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::metadata::{HtmlCommentMetadataHandler, MetadataHandler};
+
+    /// The comment with metadata appended, checking the metadata can still be read back
+    fn with_metadata(comment: &str) -> String {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+        };
+        let full_comment = metadata_handler
+            .add_metadata_to_comment(&comment, &"id")
+            .unwrap();
+        assert_eq!(
+            metadata_handler
+                .get_metadata_from_comment::<String>(&full_comment)
+                .unwrap()
+                .unwrap(),
+            "id"
+        );
+        full_comment
+    }
+
+    #[test]
+    fn test_parse_trailer() {
+        assert_eq!(
+            Trailer::from_str("Generated-by=tool @ run 123").unwrap(),
+            Trailer {
+                key: "Generated-by".to_owned(),
+                value: "tool @ run 123".to_owned(),
+            }
+        );
+        assert_eq!(Trailer::from_str("a=b=c").unwrap().value, "b=c");
+        assert!(Trailer::from_str("no_value").is_err());
+        assert!(Trailer::from_str("=value").is_err());
+    }
+
+    #[test]
+    fn test_append_trailers() {
+        assert_eq!(append_trailers("Some comment", &[]), "Some comment");
+        let trailers = vec![
+            Trailer::from_str("Generated-by=tool").unwrap(),
+            Trailer::from_str("Run=123").unwrap(),
+        ];
+        assert_eq!(
+            append_trailers("Some comment", &trailers),
+            "Some comment\n\n---\nGenerated-by: tool\nRun: 123"
+        );
+    }
+
+    #[test]
+    fn test_remove_trailers() {
+        let trailers = vec![
+            Trailer::from_str("Generated-by=tool").unwrap(),
+            Trailer::from_str("Run=124").unwrap(),
+        ];
+        assert_eq!(
+            remove_trailers(
+                "Some comment\n\n---\nGenerated-by: tool\nRun: 123",
+                &trailers
+            ),
+            "Some comment"
+        );
+        // A horizontal rule of the content isn't a trailer block
+        let ruled = "Some comment\n\n---\nMore content";
+        assert_eq!(remove_trailers(ruled, &trailers), ruled);
+        assert_eq!(
+            remove_trailers("Some comment\n\n---\nRun: 1", &[]),
+            "Some comment\n\n---\nRun: 1"
+        );
+    }
+
+    #[test]
+    fn test_frame_comment() {
+        assert_eq!(frame_comment("Body", None, None), "Body");
+        assert_eq!(
+            frame_comment("Body", Some("# Report"), Some("_Generated by CI_")),
+            "# Report\n\nBody\n\n_Generated by CI_"
+        );
+        assert_eq!(
+            frame_comment("Body", None, Some("Footer")),
+            "Body\n\nFooter"
+        );
+
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+        };
+        let full_comment = metadata_handler
+            .add_metadata_to_comment(
+                &frame_comment("Body", Some("Header"), Some("Footer")),
+                &"id",
+            )
+            .unwrap();
+        assert!(full_comment.starts_with("Header\n\nBody\n\nFooter\n\n<!-- aaaa"));
+        assert_eq!(
+            metadata_handler.remove_metadata_from_comment(&full_comment),
+            "Header\n\nBody\n\nFooter"
+        );
+    }
+
+    #[test]
+    fn test_truncate_comment() {
+        assert_eq!(truncate_comment("Some comment", 12), "Some comment");
+        assert_eq!(
+            truncate_comment("Some comment that is too long", 21),
+            "Some\n\n... [truncated]"
+        );
+
+        let oversized = "é".repeat(MAX_COMMENT_LENGTH);
+        let overhead = with_metadata("").chars().count();
+        let full_comment =
+            with_metadata(&truncate_comment(&oversized, MAX_COMMENT_LENGTH - overhead));
+        assert_eq!(full_comment.chars().count(), MAX_COMMENT_LENGTH);
+        assert!(full_comment.contains("... [truncated]"));
+    }
+
+    #[test]
+    fn test_split_comment() {
+        assert_eq!(split_comment("Some comment", 12), vec!["Some comment"]);
+
+        let line = format!("{}\n", "a".repeat(99));
+        let comment = line.repeat(MAX_COMMENT_LENGTH / 100 + 1);
+        let parts = split_comment(&comment, MAX_COMMENT_LENGTH);
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].starts_with("(part 1 of 2)\n\n"));
+        assert!(parts[1].starts_with("(part 2 of 2)\n\n"));
+        assert!(parts
+            .iter()
+            .all(|part| part.chars().count() <= MAX_COMMENT_LENGTH));
+        let rejoined: String = parts
+            .iter()
+            .map(|part| part.splitn(2, "\n\n").nth(1).unwrap())
+            .collect();
+        assert_eq!(rejoined, comment);
+    }
+
+    #[test]
+    fn test_split_comment_long_line() {
+        let parts = split_comment(&"a".repeat(100), 60);
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|part| part.chars().count() <= 60));
+    }
+
+    #[test]
+    fn test_expand_env() {
+        let lookup = |name: &str| match name {
+            "BUILD_URL" => Some("https://ci/1".to_owned()),
+            "COMMIT_SHA" => Some("abc123".to_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_env("Build ${BUILD_URL} of ${COMMIT_SHA}", lookup, false).unwrap(),
+            "Build https://ci/1 of abc123"
+        );
+        assert_eq!(
+            expand_env("Costs $$5, ${UNKNOWN} and $HOME", lookup, false).unwrap(),
+            "Costs $5, ${UNKNOWN} and $HOME"
+        );
+        assert_eq!(
+            expand_env("$${BUILD_URL}", lookup, false).unwrap(),
+            "${BUILD_URL}"
+        );
+        assert!(expand_env("${UNKNOWN}", lookup, true).is_err());
+    }
+
+    #[test]
+    fn test_fill_template() {
+        let lookup = |name: &str| match name {
+            "pr_number" => Some("42".to_owned()),
+            "org" => Some("my-org".to_owned()),
+            "repo" => Some("my-repo".to_owned()),
+            "branch" => Some("feature".to_owned()),
+            _ => None,
+        };
+        assert_eq!(fill_template("PR #{{pr_number}}", lookup), "PR #42");
+        assert_eq!(
+            fill_template("{{org}}/{{ repo }}", lookup),
+            "my-org/my-repo"
+        );
+        assert_eq!(fill_template("On {{branch}}", lookup), "On feature");
+        assert_eq!(
+            fill_template("{{unknown}} and {pr_number} and ${pr_number}", lookup),
+            "{{unknown}} and {pr_number} and ${pr_number}"
+        );
+        assert_eq!(
+            template_placeholders("{{pr_number}} {{ branch }} {{}}"),
+            vec!["pr_number", "branch"]
+        );
+    }
+
+    #[test]
+    fn test_normalize_eol() {
+        assert_eq!(normalize_eol("a\r\nb\r\n\r\nc\n"), "a\nb\n\nc\n");
+        assert_eq!(normalize_eol("no line ending"), "no line ending");
+
+        assert!(!with_metadata(&normalize_eol("Some\r\ncomment\r\n")).contains('\r'));
+    }
+
+    #[test]
+    fn test_lint_markdown() {
+        let well_formed = "## Report\n\
+                           <!-- generated -->\n\
+                           ```rust\n\
+                           let html = \"<!--\";\n\
+                           ```\n\
+                           ~~~~\n\
+                           ```\n\
+                           ~~~~~\n\
+                           <!--\n\
+                           hidden\n\
+                           -->\n\
+                           Done";
+        assert_eq!(lint_markdown(well_formed), Vec::<String>::new());
+        assert_eq!(lint_markdown(""), Vec::<String>::new());
+
+        assert_eq!(
+            lint_markdown("## Report\n```diff\n- old\n+ new\n``\n"),
+            vec![
+                "The code block opened on line 2 is never closed, the rest of the comment being \
+                 rendered as code"
+            ]
+        );
+        assert_eq!(
+            lint_markdown("Some <!-- note\n\nthe rest\n"),
+            vec![
+                "The HTML comment opened on line 1 is never closed, hiding the rest of the comment"
+            ]
+        );
+        // Indented code isn't a fence
+        assert_eq!(lint_markdown("    ```\ncode"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(content_hash("Some comment"), content_hash("Some comment"));
+        assert_ne!(content_hash("Some comment"), content_hash("Some comment!"));
+        assert_eq!(content_hash("").len(), 64);
+    }
+
+    #[test]
+    fn test_trailers_dont_interfere_with_metadata() {
+        let trailers = vec![Trailer::from_str("Run=123").unwrap()];
+        let with_trailers = append_trailers("Some comment", &trailers);
+        with_metadata(&with_trailers);
+        // The hash recorded in the metadata is that of the content alone
+        assert_eq!(
+            content_hash(remove_trailers(&with_trailers, &trailers)),
+            content_hash("Some comment")
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use super::error::GithubResult;
+use super::GithubAPI;
+use anyhow::{Context, Result};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The media type of the Github Apps api, still in preview
+const APPS_PREVIEW_MEDIA_TYPE: &str = "application/vnd.github.machine-man-preview+json";
+
+/// Github rejects app tokens valid for more than 10 minutes
+const JWT_VALIDITY: Duration = Duration::from_secs(9 * 60);
+
+/// Tolerance for the clock of the machine being ahead of Github's
+const CLOCK_DRIFT: Duration = Duration::from_secs(60);
+
+/// Authenticates as a Github App rather than with a personal token
+#[derive(Clone)]
+pub struct AppCredentials {
+    pub app_id: u64,
+    /// The PEM encoded RSA private key of the app
+    pub private_key: Vec<u8>,
+}
+
+impl fmt::Debug for AppCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "AppCredentials {{ app_id: {}, private_key: '************' }}",
+            self.app_id
+        )
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct JwtClaims {
+    iat: u64,
+    exp: u64,
+    iss: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Installation {
+    id: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct InstallationToken {
+    token: String,
+}
+
+impl AppCredentials {
+    /// The short-lived token identifying the app, to exchange for an installation token
+    pub fn jwt(&self, now: SystemTime) -> Result<String> {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let claims = JwtClaims {
+            iat: (now - CLOCK_DRIFT).as_secs(),
+            exp: (now + JWT_VALIDITY).as_secs(),
+            iss: self.app_id.to_string(),
+        };
+        let key = EncodingKey::from_rsa_pem(&self.private_key)
+            .context("Invalid Github App private key")?;
+        encode(&Header::new(Algorithm::RS256), &claims, &key).context("Failed to sign the JWT")
+    }
+}
+
+impl GithubAPI {
+    /// Exchange the app JWT for a token of the installation of the app on the repository
+    pub fn installation_token(
+        &self,
+        jwt: &str,
+        repo_owner: &str,
+        repo_name: &str,
+    ) -> GithubResult<String> {
+        let authorization = format!("Bearer {}", jwt);
+        let res = self.send(self.request_with_authorization(
+            Method::GET,
+            &format!("repos/{}/{}/installation", repo_owner, repo_name),
+            APPS_PREVIEW_MEDIA_TYPE,
+            &authorization,
+        )?)?;
+        let installation: Installation = Self::check_status(res, StatusCode::OK)?.json()?;
+
+        let res = self.send(self.request_with_authorization(
+            Method::POST,
+            &format!("app/installations/{}/access_tokens", installation.id),
+            APPS_PREVIEW_MEDIA_TYPE,
+            &authorization,
+        )?)?;
+        let token: InstallationToken = Self::check_status(res, StatusCode::CREATED)?.json()?;
+        Ok(token.token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::mock_api;
+
+    #[test]
+    fn test_installation_token() {
+        let installation = mockito::mock("GET", "/repos/org/repo/installation")
+            .match_header("authorization", "Bearer some.jwt.token")
+            .with_status(200)
+            .with_body(r#"{"id": 42, "app_id": 1}"#)
+            .create();
+        let access_token = mockito::mock("POST", "/app/installations/42/access_tokens")
+            .match_header("authorization", "Bearer some.jwt.token")
+            .with_status(201)
+            .with_body(
+                r#"{"token": "v1.installation_token", "expires_at": "2016-07-11T22:14:10Z"}"#,
+            )
+            .create();
+        assert_eq!(
+            mock_api()
+                .installation_token("some.jwt.token", "org", "repo")
+                .unwrap(),
+            "v1.installation_token"
+        );
+        installation.assert();
+        access_token.assert();
+    }
+}
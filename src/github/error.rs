@@ -0,0 +1,126 @@
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::time::Duration;
+use thiserror::Error;
+
+/// The errors returned by the Github api
+#[derive(Error, Debug)]
+pub enum GithubError {
+    /// The resource (PR, comment, repo, ...) doesn't exist or isn't visible with the token
+    #[error("Not found: {0}")]
+    NotFound(String),
+    /// No open PR matches the git reference or the commit
+    #[error("No PR found: {0}")]
+    NoPullRequest(String),
+    /// The token is missing, invalid or expired
+    #[error("Github rejected the token")]
+    Unauthorized,
+    /// The token is valid but doesn't give access to the resource
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    /// The rate limit is still exceeded after waiting for it to reset
+    #[error("Github rate limit exceeded")]
+    RateLimited,
+    /// The api url couldn't be built
+    #[error("Invalid Github api url")]
+    Url(#[from] url::ParseError),
+    /// Github didn't answer within the request timeout
+    #[error("Request to Github timed out after {0:?}")]
+    Timeout(Duration),
+    /// The request couldn't be sent or the response couldn't be read
+    #[error("Request to Github failed")]
+    Http(#[from] reqwest::Error),
+    /// The request couldn't be sent, along with the cause with the credentials masked
+    #[error("Request to Github failed: {0}")]
+    Request(String),
+    /// Github answered with an unexpected status, along with the error it reported
+    #[error("Github returned unexpected status {0} : {1}")]
+    Unexpected(StatusCode, String),
+    /// The GraphQL api returned errors
+    #[error("Github returned GraphQL errors : {0}")]
+    GraphQL(String),
+}
+
+pub type GithubResult<T> = Result<T, GithubError>;
+
+/// A detailed error in a Github error response
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+struct GithubErrorDetail {
+    #[serde(default)]
+    resource: Option<String>,
+    #[serde(default)]
+    field: Option<String>,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// The body of the Github error responses
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+struct GithubErrorBody {
+    message: String,
+    #[serde(default)]
+    errors: Vec<GithubErrorDetail>,
+}
+
+/// Describe the error reported in the body of a Github error response, falling back to the raw
+/// body when it isn't the usual Github json
+pub fn describe_error_body(body: &str) -> String {
+    match serde_json::from_str::<GithubErrorBody>(body) {
+        Ok(error) if error.errors.is_empty() => error.message,
+        Ok(error) => {
+            let details: Vec<String> = error
+                .errors
+                .iter()
+                .map(|detail| match detail {
+                    GithubErrorDetail {
+                        message: Some(message),
+                        ..
+                    } => message.clone(),
+                    GithubErrorDetail {
+                        resource,
+                        field,
+                        code,
+                        ..
+                    } => format!(
+                        "{} {} {}",
+                        resource.as_deref().unwrap_or_default(),
+                        field.as_deref().unwrap_or_default(),
+                        code.as_deref().unwrap_or_default()
+                    )
+                    .trim()
+                    .to_owned(),
+                })
+                .collect();
+            format!("{}: {}", error.message, details.join(", "))
+        }
+        Err(_) => body.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_error_body() {
+        assert_eq!(
+            describe_error_body(
+                r#"{"message": "Validation Failed", "errors": [{"resource": "IssueComment", "code": "custom", "field": "body", "message": "body is too long (maximum is 65536 characters)"}]}"#
+            ),
+            "Validation Failed: body is too long (maximum is 65536 characters)"
+        );
+        assert_eq!(
+            describe_error_body(
+                r#"{"message": "Validation Failed", "errors": [{"resource": "IssueComment", "field": "body", "code": "missing_field"}]}"#
+            ),
+            "Validation Failed: IssueComment body missing_field"
+        );
+        assert_eq!(
+            describe_error_body(r#"{"message": "Bad credentials"}"#),
+            "Bad credentials"
+        );
+        assert_eq!(describe_error_body("<html>502</html>"), "<html>502</html>");
+    }
+}
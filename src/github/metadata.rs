@@ -1,21 +1,76 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
 use serde;
+use serde::{Deserialize, Serialize};
 
-/// Append a HTML comment to the content of the message containing the metadata as json
-pub struct HtmlCommentMetadataHandler {
-    pub metadata_id: String,
+/// Identifies the comments generated by the tool when no other identifier is configured
+pub const DEFAULT_METADATA_ID: &str = "pr_commentator :";
+
+/// Marks the metadata encoded in base64, the metadata of older versions being plain JSON
+const BASE64_MARKER: &str = "base64:";
+
+/// The metadata stored in the comments generated by the tool
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommentMetadata {
+    /// Identifies the comment to overwrite
+    #[serde(default)]
+    pub identifier: Option<String>,
+    /// The name of the job that posted the comment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<String>,
+    /// The build number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<String>,
+    /// The commit sha the comment relates to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// The version of the tool that posted the comment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_version: Option<String>,
+    /// When the comment was last written by the tool
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<DateTime<Utc>>,
+    /// The hash of the content, trailers excluded, to detect the content didn't change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// Identifies the run that posted the comment, to detect a retried run posting it again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
 }
 
-impl HtmlCommentMetadataHandler {
-    fn prefix(&self) -> String {
-        format!("\n\n<!-- {}", self.metadata_id)
-    }
+/// The position of the start marker in the comment and of the metadata following it. The marker
+/// is looked up without its trailing whitespace, which Github may trim, and must be followed by
+/// whitespace or by the metadata itself so that an identifier doesn't match the longer ones it
+/// prefixes, e.g. `ci` and `ci-lint`. The whitespace between it and the metadata is skipped.
+fn find_start_marker(comment: &str, start_marker: &str) -> Option<(usize, usize)> {
+    let start_marker = start_marker.trim_end();
+    comment.match_indices(start_marker).find_map(|(start, _)| {
+        let after_marker = &comment[start + start_marker.len()..];
+        let metadata = after_marker.trim_start();
+        // Written right after the marker by the older versions, plain JSON for the oldest
+        let whole_id = metadata.len() < after_marker.len()
+            || metadata.starts_with(BASE64_MARKER)
+            || metadata.starts_with(&['"', '{', '['][..])
+            || metadata.starts_with("null");
+        if whole_id {
+            Some((start, comment.len() - metadata.len()))
+        } else {
+            None
+        }
+    })
+}
 
-    fn suffix(&self) -> String {
-        format!(" -->")
-    }
+/// Stores the metadata in the comments as base64 encoded json, between markers that hide it when
+/// the comment is rendered. Only the markers are to be provided, the encoding being shared.
+pub trait MetadataHandler {
+    /// Precedes the metadata, identifying the comments generated by the tool. A space separates
+    /// it from the metadata when written.
+    fn start_marker(&self) -> String;
+
+    /// Follows the metadata, which must not contain it
+    fn end_marker(&self) -> String;
 
-    pub fn add_metadata_to_comment<T: std::fmt::Display, M: serde::Serialize>(
+    fn add_metadata_to_comment<T: std::fmt::Display, M: serde::Serialize>(
         &self,
         comment: &T,
         metadata: &M,
@@ -24,36 +79,156 @@ impl HtmlCommentMetadataHandler {
             .context("Failed to serialize metadata")
             .map(|metadata_json| {
                 format!(
-                    "{}{}{}{}",
+                    "{}{} {}{}{}",
                     comment,
-                    self.prefix(),
-                    metadata_json,
-                    self.suffix()
+                    self.start_marker().trim_end(),
+                    BASE64_MARKER,
+                    base64::encode(metadata_json),
+                    self.end_marker()
                 )
             })
     }
 
-    pub fn get_metadata_from_comment<M: serde::de::DeserializeOwned>(
+    /// The content of the comment, without the metadata
+    fn remove_metadata_from_comment<'a>(&self, comment: &'a str) -> &'a str {
+        match find_start_marker(comment, &self.start_marker()) {
+            Some((start, _)) => &comment[..start],
+            None => comment,
+        }
+    }
+
+    /// Append the metadata to the comment, recording the current time as its timestamp
+    fn add_comment_metadata<T: std::fmt::Display>(
+        &self,
+        comment: &T,
+        metadata: &CommentMetadata,
+    ) -> Result<String> {
+        let metadata = CommentMetadata {
+            timestamp: Some(Utc::now()),
+            ..metadata.clone()
+        };
+        self.add_metadata_to_comment(comment, &metadata)
+    }
+
+    /// Retrieve the metadata of a comment generated by the tool, also accepting the legacy
+    /// metadata made only of the identifier
+    fn get_comment_metadata(&self, comment: &str) -> Option<Result<CommentMetadata>> {
+        self.get_metadata_from_comment::<serde_json::Value>(comment)
+            .map(|metadata| {
+                metadata.and_then(|value| {
+                    serde_json::from_value::<CommentMetadata>(value.clone())
+                        .or_else(|_| {
+                            serde_json::from_value::<Option<String>>(value).map(|identifier| {
+                                CommentMetadata {
+                                    identifier,
+                                    ..CommentMetadata::default()
+                                }
+                            })
+                        })
+                        .context("Unsupported metadata")
+                })
+            })
+    }
+
+    fn get_metadata_from_comment<M: serde::de::DeserializeOwned>(
         &self,
         comment: &str,
     ) -> Option<Result<M>> {
-        let prefix = &self.prefix();
-        let position: Option<(usize, usize)> = comment.find(prefix).and_then(|start| {
-            let meta_start = start + prefix.len();
-            let end = comment.find(&self.suffix());
-            end.map(|e| (meta_start, e))
-        });
-        if let Some((start, end)) = position {
-            Some(serde_json::from_str(&comment[start..end]).context("Failed to parse metadata"))
+        let position: Option<(usize, usize)> = find_start_marker(comment, &self.start_marker())
+            .and_then(|(_, meta_start)| {
+                let end = comment[meta_start..].find(&self.end_marker());
+                end.map(|e| (meta_start, meta_start + e))
+            });
+        let (start, end) = position?;
+        let metadata = &comment[start..end];
+        Some(
+            if let Some(encoded) = metadata.strip_prefix(BASE64_MARKER) {
+                base64::decode(encoded)
+                    .context("Failed to decode metadata")
+                    .and_then(|json| {
+                        serde_json::from_slice(&json).context("Failed to parse metadata")
+                    })
+            } else {
+                serde_json::from_str(metadata).context("Failed to parse metadata")
+            },
+        )
+    }
+}
+
+/// Append a HTML comment to the content of the message containing the metadata, so that it
+/// survives the markdown sanitizers
+pub struct HtmlCommentMetadataHandler {
+    pub metadata_id: String,
+}
+
+impl MetadataHandler for HtmlCommentMetadataHandler {
+    fn start_marker(&self) -> String {
+        format!("\n\n<!-- {}", self.metadata_id)
+    }
+
+    fn end_marker(&self) -> String {
+        " -->".to_owned()
+    }
+}
+
+/// Append the metadata between custom delimiters, e.g. for contexts not rendering HTML comments
+pub struct DelimitedMetadataHandler {
+    pub metadata_id: String,
+    pub start: String,
+    pub end: String,
+}
+
+impl DelimitedMetadataHandler {
+    /// Check the delimiter preceding the metadata isn't empty
+    pub fn check_start(start: &str) -> Result<()> {
+        if start.is_empty() {
+            return Err(anyhow!("The metadata start delimiter can't be empty"));
+        }
+        Ok(())
+    }
+
+    /// Check the delimiter following the metadata isn't empty and can't be found within the
+    /// encoded metadata it follows, which would cut the metadata short
+    pub fn check_end(end: &str) -> Result<()> {
+        let is_base64 = |text: &str| {
+            text.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/' || c == '=')
+        };
+        // Either within the base64 payload, or starting within the marker preceding it
+        let in_metadata = match end.find(':') {
+            Some(colon) => BASE64_MARKER.ends_with(&end[..=colon]) && is_base64(&end[colon + 1..]),
+            None => is_base64(end),
+        };
+        if end.is_empty() {
+            Err(anyhow!("The metadata end delimiter can't be empty"))
+        } else if in_metadata {
+            Err(anyhow!(
+                "The metadata end delimiter `{}` could be part of the base64 encoded metadata",
+                end
+            ))
         } else {
-            None
+            Ok(())
         }
     }
 }
 
+impl MetadataHandler for DelimitedMetadataHandler {
+    fn start_marker(&self) -> String {
+        format!("\n\n{}{}", self.start, self.metadata_id)
+    }
+
+    fn end_marker(&self) -> String {
+        self.end.clone()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::HtmlCommentMetadataHandler;
+    use super::{
+        CommentMetadata, DelimitedMetadataHandler, HtmlCommentMetadataHandler, MetadataHandler,
+        DEFAULT_METADATA_ID,
+    };
+    use chrono::{Duration, Utc};
 
     #[test]
     fn test_add_get_metadata() {
@@ -62,18 +237,29 @@ mod tests {
         let metadata_handler = HtmlCommentMetadataHandler {
             metadata_id: "aaaa".to_string(),
         };
-        let expected_full_com = "Some comment\n\n<!-- aaaa[1,2] -->";
+        let expected_full_com = format!(
+            "Some comment\n\n<!-- aaaa base64:{} -->",
+            base64::encode("[1,2]")
+        );
 
         assert_eq!(
             expected_full_com,
-            &metadata_handler
+            metadata_handler
                 .add_metadata_to_comment(&comment, &metadata)
                 .unwrap()
         );
         assert_eq!(
             &metadata,
             &metadata_handler
-                .get_metadata_from_comment::<Vec<u64>>(expected_full_com)
+                .get_metadata_from_comment::<Vec<u64>>(&expected_full_com)
+                .unwrap()
+                .unwrap()
+        );
+        // Metadata written by older versions
+        assert_eq!(
+            &metadata,
+            &metadata_handler
+                .get_metadata_from_comment::<Vec<u64>>("Some comment\n\n<!-- aaaa[1,2] -->")
                 .unwrap()
                 .unwrap()
         );
@@ -81,4 +267,264 @@ mod tests {
             .get_metadata_from_comment::<()>(comment)
             .is_none());
     }
+
+    #[test]
+    fn test_append_to_comment() {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+        };
+        let append = |previous: &str, content: &str| {
+            metadata_handler
+                .add_metadata_to_comment(
+                    &format!(
+                        "{}\n\n{}",
+                        metadata_handler.remove_metadata_from_comment(previous),
+                        content
+                    ),
+                    &"id",
+                )
+                .unwrap()
+        };
+        let first = metadata_handler
+            .add_metadata_to_comment(&"first", &"id")
+            .unwrap();
+        let second = append(&first, "second");
+        let third = append(&second, "third");
+
+        assert_eq!(
+            third,
+            format!(
+                "first\n\nsecond\n\nthird\n\n<!-- aaaa base64:{} -->",
+                base64::encode("\"id\"")
+            )
+        );
+        assert_eq!(
+            metadata_handler.remove_metadata_from_comment(&third),
+            "first\n\nsecond\n\nthird"
+        );
+        assert_eq!(
+            metadata_handler.remove_metadata_from_comment("no metadata"),
+            "no metadata"
+        );
+    }
+
+    #[test]
+    fn test_structured_metadata() {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+        };
+        let metadata = CommentMetadata {
+            identifier: Some("id".to_owned()),
+            job: Some("lint".to_owned()),
+            build: Some("123".to_owned()),
+            commit: None,
+            tool_version: Some("0.2.0".to_owned()),
+            timestamp: None,
+            content_hash: None,
+            run_id: Some("1234-1".to_owned()),
+        };
+        let full_comment = metadata_handler
+            .add_metadata_to_comment(&"Some comment", &metadata)
+            .unwrap();
+        assert_eq!(
+            full_comment,
+            format!(
+                "Some comment\n\n<!-- aaaa base64:{} -->",
+                base64::encode(
+                    "{\"identifier\":\"id\",\"job\":\"lint\",\"build\":\"123\",\
+                     \"tool_version\":\"0.2.0\",\"run_id\":\"1234-1\"}"
+                )
+            )
+        );
+        assert_eq!(
+            metadata_handler
+                .get_comment_metadata(&full_comment)
+                .unwrap()
+                .unwrap(),
+            metadata
+        );
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+        };
+        let metadata = CommentMetadata {
+            identifier: Some("lint --> é 日本".to_owned()),
+            ..CommentMetadata::default()
+        };
+        let full_comment = metadata_handler
+            .add_metadata_to_comment(&"Some comment -->", &metadata)
+            .unwrap();
+        assert_eq!(full_comment.matches("-->").count(), 2);
+        assert_eq!(
+            metadata_handler
+                .get_comment_metadata(&full_comment)
+                .unwrap()
+                .unwrap(),
+            metadata
+        );
+        assert_eq!(
+            metadata_handler.remove_metadata_from_comment(&full_comment),
+            "Some comment -->"
+        );
+    }
+
+    #[test]
+    fn test_trimmed_metadata_id() {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: DEFAULT_METADATA_ID.to_owned(),
+        };
+        let metadata = CommentMetadata {
+            identifier: Some("id".to_owned()),
+            ..CommentMetadata::default()
+        };
+        let full_comment = metadata_handler
+            .add_metadata_to_comment(&"Some comment", &metadata)
+            .unwrap();
+        let trimmed = full_comment.replace("pr_commentator : ", "pr_commentator :");
+        let wrapped = full_comment.replace("pr_commentator : ", "pr_commentator :\n");
+        for comment in &[trimmed, wrapped] {
+            assert_eq!(
+                metadata_handler
+                    .get_comment_metadata(comment)
+                    .unwrap()
+                    .unwrap(),
+                metadata
+            );
+            assert_eq!(
+                metadata_handler.remove_metadata_from_comment(comment),
+                "Some comment"
+            );
+        }
+        // Legacy metadata
+        assert_eq!(
+            metadata_handler
+                .get_metadata_from_comment::<String>(
+                    "Some comment\n\n<!-- pr_commentator :\"id\" -->"
+                )
+                .unwrap()
+                .unwrap(),
+            "id"
+        );
+    }
+
+    #[test]
+    fn test_prefixing_metadata_id() {
+        let ci = HtmlCommentMetadataHandler {
+            metadata_id: "ci".to_owned(),
+        };
+        let ci_lint = HtmlCommentMetadataHandler {
+            metadata_id: "ci-lint".to_owned(),
+        };
+        let metadata = CommentMetadata {
+            identifier: Some("lint".to_owned()),
+            ..CommentMetadata::default()
+        };
+        let lint_comment = ci_lint.add_metadata_to_comment(&"Lint", &metadata).unwrap();
+        assert!(lint_comment.contains("<!-- ci-lint base64:"));
+        assert!(ci.get_comment_metadata(&lint_comment).is_none());
+        assert_eq!(ci.remove_metadata_from_comment(&lint_comment), lint_comment);
+        // Found after the marker of the longer identifier
+        let both = format!(
+            "{}{}",
+            lint_comment,
+            ci.add_metadata_to_comment(&"", &metadata).unwrap()
+        );
+        assert_eq!(ci.get_comment_metadata(&both).unwrap().unwrap(), metadata);
+    }
+
+    #[test]
+    fn test_delimited_metadata() {
+        let metadata_handler = DelimitedMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+            start: "[//]: # (".to_owned(),
+            end: ")".to_owned(),
+        };
+        let metadata = CommentMetadata {
+            identifier: Some("lint (main)".to_owned()),
+            build: Some("123".to_owned()),
+            ..CommentMetadata::default()
+        };
+        let full_comment = metadata_handler
+            .add_metadata_to_comment(&"Some comment", &metadata)
+            .unwrap();
+        assert!(full_comment.starts_with("Some comment\n\n[//]: # (aaaa base64:"));
+        assert!(full_comment.ends_with(')'));
+        assert_eq!(
+            metadata_handler
+                .get_comment_metadata(&full_comment)
+                .unwrap()
+                .unwrap(),
+            metadata
+        );
+        assert_eq!(
+            metadata_handler.remove_metadata_from_comment(&full_comment),
+            "Some comment"
+        );
+        // The markers of another handler aren't recognized
+        let html_handler = HtmlCommentMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+        };
+        assert!(html_handler.get_comment_metadata(&full_comment).is_none());
+    }
+
+    #[test]
+    fn test_check_delimiters() {
+        assert!(DelimitedMetadataHandler::check_start("[//]: # (").is_ok());
+        assert!(DelimitedMetadataHandler::check_start("").is_err());
+        for end in &[")", " -->", "]", "a)", ":)"] {
+            assert!(DelimitedMetadataHandler::check_end(end).is_ok(), "{}", end);
+        }
+        for end in &["", "=", "end", "A/b+", "4:", ":", "base64:Zm9v", "e64:x="] {
+            assert!(DelimitedMetadataHandler::check_end(end).is_err(), "{}", end);
+        }
+    }
+
+    #[test]
+    fn test_legacy_metadata() {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+        };
+        assert_eq!(
+            metadata_handler
+                .get_comment_metadata("Some comment\n\n<!-- aaaa\"id\" -->")
+                .unwrap()
+                .unwrap(),
+            CommentMetadata {
+                identifier: Some("id".to_owned()),
+                ..CommentMetadata::default()
+            }
+        );
+        assert_eq!(
+            metadata_handler
+                .get_comment_metadata("Some comment\n\n<!-- aaaanull -->")
+                .unwrap()
+                .unwrap(),
+            CommentMetadata::default()
+        );
+        assert!(metadata_handler
+            .get_comment_metadata("Some comment\n\n<!-- aaaa[1] -->")
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_metadata_timestamp() {
+        let metadata_handler = HtmlCommentMetadataHandler {
+            metadata_id: "aaaa".to_string(),
+        };
+        let full_comment = metadata_handler
+            .add_comment_metadata(&"Some comment", &CommentMetadata::default())
+            .unwrap();
+        assert!(full_comment.starts_with("Some comment\n\n<!-- aaaa"));
+        let timestamp = metadata_handler
+            .get_comment_metadata(&full_comment)
+            .unwrap()
+            .unwrap()
+            .timestamp
+            .unwrap();
+        assert!(Utc::now().signed_duration_since(timestamp) < Duration::seconds(5));
+    }
 }
@@ -0,0 +1,73 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use pr_commentator::github::{
+    AuthScheme, ClientCache, GithubAPI, DEFAULT_API_VERSION, DEFAULT_MEDIA_TYPE, DEFAULT_USER_AGENT,
+};
+use pr_commentator::{run, CommentReport, Config, Outcome, PullRequestLookup};
+use url::Url;
+
+fn mock_api() -> GithubAPI {
+    GithubAPI {
+        base_url: Url::from_str(&mockito::server_url()).unwrap(),
+        token: "some_token".to_owned(),
+        auth_scheme: AuthScheme::Token,
+        user_agent: DEFAULT_USER_AGENT.to_owned(),
+        rate_limit_retries: 0,
+        rate_limit_max_wait: Duration::from_secs(1),
+        timeout: Duration::from_secs(5),
+        proxy: None,
+        accept: DEFAULT_MEDIA_TYPE.to_owned(),
+        api_version: DEFAULT_API_VERSION.to_owned(),
+        retries: 0,
+        retry_base: Duration::from_millis(1),
+        retry_writes: false,
+        compression: true,
+        client_cache: ClientCache::default(),
+    }
+}
+
+#[test]
+fn test_run_overwrites_previous_comment() {
+    let list = mockito::mock("GET", "/repos/lib/run/issues/5/comments?per_page=100")
+        .with_status(200)
+        .with_body(
+            r#"[
+                {"id": 1, "node_id": "IC_1", "html_url": "https://github.com/lib/run/pull/5#issuecomment-1", "body": "Not generated"},
+                {"id": 2, "node_id": "IC_2", "html_url": "https://github.com/lib/run/pull/5#issuecomment-2", "body": "Previous\n\n<!-- pr_commentator : base64:e30= -->"}
+            ]"#,
+        )
+        .create();
+    let edit = mockito::mock("PATCH", "/repos/lib/run/issues/comments/2")
+        .match_body(mockito::Matcher::Regex(r#"^\{"body":"New comment"#.to_owned()))
+        .with_status(200)
+        .with_body(
+            r#"{"id": 2, "node_id": "IC_2", "html_url": "https://github.com/lib/run/pull/5#issuecomment-2", "body": "New comment"}"#,
+        )
+        .create();
+
+    let config = Config::new(
+        mock_api(),
+        "lib",
+        "run",
+        PullRequestLookup::Number(5),
+        "New comment",
+    );
+    assert_eq!(
+        run(config).unwrap(),
+        Outcome {
+            comments: vec![CommentReport {
+                pr_number: Some(5),
+                issue_number: None,
+                commit: None,
+                action: "edited",
+                comment_id: 2,
+                html_url: "https://github.com/lib/run/pull/5#issuecomment-2".to_owned(),
+                overwrite_matched: true,
+                overwrite_matches: 1,
+            }],
+        }
+    );
+    list.assert();
+    edit.assert();
+}